@@ -0,0 +1,191 @@
+use result::{error, Result};
+use std::collections::BTreeMap;
+use std::str;
+
+/// A bencoded value, as used by the nREPL wire protocol.
+///
+/// Dictionary keys are kept as raw bytes (rather than `String`) since nREPL
+/// technically allows arbitrary byte-string keys, but in practice they're
+/// always ASCII op/response field names.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+#[derive(Debug, Fail)]
+enum Error {
+    #[fail(display = "unexpected end of input while decoding bencode")]
+    Eof,
+    #[fail(display = "invalid bencode: {}", reason)]
+    Invalid { reason: String },
+}
+
+impl Value {
+    pub fn str(s: &str) -> Self {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+
+    pub fn dict(pairs: Vec<(&str, Value)>) -> Self {
+        let mut map = BTreeMap::new();
+        for (key, value) in pairs {
+            map.insert(key.as_bytes().to_vec(), value);
+        }
+        Value::Dict(map)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(map) => map.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Bytes(bytes) => str::from_utf8(bytes).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(n) => {
+                out.push(b'i');
+                out.extend(n.to_string().into_bytes());
+                out.push(b'e');
+            }
+            Value::Bytes(bytes) => {
+                out.extend(bytes.len().to_string().into_bytes());
+                out.push(b':');
+                out.extend(bytes);
+            }
+            Value::List(items) => {
+                out.push(b'l');
+                for item in items {
+                    item.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(map) => {
+                out.push(b'd');
+                // BTreeMap already iterates keys in sorted order.
+                for (key, value) in map {
+                    Value::Bytes(key.clone()).encode_into(out);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+/// Decodes a single bencoded value from the front of `input`, returning the
+/// value along with the number of bytes consumed.
+pub fn decode(input: &[u8]) -> Result<(Value, usize)> {
+    match input.first() {
+        None => Err(error(Error::Eof)),
+        Some(b'i') => decode_int(input),
+        Some(b'l') => decode_list(input),
+        Some(b'd') => decode_dict(input),
+        Some(c) if c.is_ascii_digit() => decode_bytes(input),
+        Some(c) => Err(error(Error::Invalid {
+            reason: format!("unexpected leading byte: {}", *c as char),
+        })),
+    }
+}
+
+fn find(input: &[u8], byte: u8, from: usize) -> Result<usize> {
+    input[from..]
+        .iter()
+        .position(|&b| b == byte)
+        .map(|pos| pos + from)
+        .ok_or_else(|| error(Error::Eof))
+}
+
+fn decode_int(input: &[u8]) -> Result<(Value, usize)> {
+    let end = find(input, b'e', 1)?;
+    let digits = str::from_utf8(&input[1..end]).map_err(|_| {
+        error(Error::Invalid {
+            reason: "non-utf8 integer".to_owned(),
+        })
+    })?;
+    let n = digits.parse().map_err(|_| {
+        error(Error::Invalid {
+            reason: format!("bad integer: {}", digits),
+        })
+    })?;
+    Ok((Value::Int(n), end + 1))
+}
+
+fn decode_bytes(input: &[u8]) -> Result<(Value, usize)> {
+    let colon = find(input, b':', 0)?;
+    let len: usize = str::from_utf8(&input[..colon])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| error(Error::Invalid {
+            reason: "bad byte string length".to_owned(),
+        }))?;
+
+    let start = colon + 1;
+    let end = start + len;
+    if end > input.len() {
+        return Err(error(Error::Eof));
+    }
+
+    Ok((Value::Bytes(input[start..end].to_vec()), end))
+}
+
+fn decode_list(input: &[u8]) -> Result<(Value, usize)> {
+    let mut pos = 1;
+    let mut items = vec![];
+
+    while input.get(pos) != Some(&b'e') {
+        let (value, consumed) = decode(&input[pos..])?;
+        items.push(value);
+        pos += consumed;
+    }
+
+    Ok((Value::List(items), pos + 1))
+}
+
+fn decode_dict(input: &[u8]) -> Result<(Value, usize)> {
+    let mut pos = 1;
+    let mut map = BTreeMap::new();
+
+    while input.get(pos) != Some(&b'e') {
+        let (key, key_len) = decode_bytes(&input[pos..])?;
+        pos += key_len;
+
+        let (value, value_len) = decode(&input[pos..])?;
+        pos += value_len;
+
+        if let Value::Bytes(key) = key {
+            map.insert(key, value);
+        }
+    }
+
+    Ok((Value::Dict(map), pos + 1))
+}
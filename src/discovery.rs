@@ -0,0 +1,118 @@
+use clojure;
+use regex;
+use regex::Regex;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Filenames Leiningen, deps.edn and shadow-cljs write next to a running
+/// REPL, each containing nothing but the port number.
+const PORT_FILES: &[&str] = &[".nrepl-port", ".shadow-cljs/nrepl.port"];
+
+/// A REPL found by walking the project tree for a port file, ready to be
+/// handed to `Pool::connect`.
+#[derive(Debug, Clone)]
+pub struct Discovered {
+    pub key: String,
+    pub addr: SocketAddr,
+    pub lang: clojure::Lang,
+    pub expr: Regex,
+}
+
+/// `regex::Regex` doesn't implement `PartialEq`, so compare `expr` by its
+/// source string instead.
+impl PartialEq for Discovered {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+            && self.addr == other.addr
+            && self.lang == other.lang
+            && self.expr.as_str() == other.expr.as_str()
+    }
+}
+
+/// Walks upward from `start`, checking each ancestor for a port file.
+pub fn scan(start: &Path) -> Vec<Discovered> {
+    let mut found = vec![];
+    let mut dir = Some(start);
+
+    while let Some(current) = dir {
+        for port_file in PORT_FILES {
+            if let Some(discovered) = read_port_file(&current.join(port_file), current) {
+                found.push(discovered);
+            }
+        }
+
+        dir = current.parent();
+    }
+
+    found
+}
+
+fn read_port_file(path: &Path, project_root: &Path) -> Option<Discovered> {
+    let contents = fs::read_to_string(path).ok()?;
+    let port: u16 = contents.trim().parse().ok()?;
+
+    Some(Discovered {
+        key: path.to_string_lossy().into_owned(),
+        addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+        lang: infer_lang(path),
+        expr: Regex::new(&format!("^{}", regex::escape(&project_root.to_string_lossy()))).ok()?,
+    })
+}
+
+/// shadow-cljs's port file lives under `.shadow-cljs/` and serves a
+/// ClojureScript REPL; a bare `.nrepl-port` is assumed to be a JVM Clojure
+/// REPL (Leiningen/deps).
+fn infer_lang(path: &Path) -> clojure::Lang {
+    if path.components().any(|c| c.as_os_str() == ".shadow-cljs") {
+        clojure::Lang::ClojureScript
+    } else {
+        clojure::Lang::Clojure
+    }
+}
+
+/// Polls `scan` on an interval and invokes `on_change` with the fresh set of
+/// discovered REPLs whenever it differs from the previous one, so that
+/// restarting a REPL (which rewrites its port file) transparently triggers
+/// a reconnect.
+pub struct Watcher {
+    stop: Arc<AtomicBool>,
+}
+
+impl Watcher {
+    pub fn start<F>(root: &Path, interval: Duration, mut on_change: F) -> Self
+    where
+        F: FnMut(Vec<Discovered>) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+        let root = root.to_path_buf();
+
+        thread::spawn(move || {
+            let mut last: Vec<Discovered> = vec![];
+
+            while !stop_thread.load(Ordering::Relaxed) {
+                let current = scan(&root);
+
+                if current != last {
+                    on_change(current.clone());
+                    last = current;
+                }
+
+                thread::sleep(interval);
+            }
+        });
+
+        Self { stop }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,198 @@
+use bencode::Value;
+use repl::Response;
+use result::Result;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+/// Builds the bencoded `clone` op sent once per connection to obtain a
+/// session id, which is then threaded through every subsequent request.
+fn clone_op() -> Value {
+    Value::dict(vec![("op", Value::str("clone"))])
+}
+
+pub fn eval_op(code: &str, ns: &str, session: &str, id: &str) -> String {
+    render(Value::dict(vec![
+        ("op", Value::str("eval")),
+        ("code", Value::str(code)),
+        ("ns", Value::str(ns)),
+        ("session", Value::str(session)),
+        ("id", Value::str(id)),
+    ]))
+}
+
+pub fn info_op(symbol: &str, ns: &str, session: &str, id: &str) -> String {
+    render(Value::dict(vec![
+        ("op", Value::str("info")),
+        ("sym", Value::str(symbol)),
+        ("ns", Value::str(ns)),
+        ("session", Value::str(session)),
+        ("id", Value::str(id)),
+    ]))
+}
+
+pub fn complete_op(prefix: &str, ns: &str, session: &str, id: &str) -> String {
+    render(Value::dict(vec![
+        ("op", Value::str("complete")),
+        ("prefix", Value::str(prefix)),
+        ("ns", Value::str(ns)),
+        ("session", Value::str(session)),
+        ("id", Value::str(id)),
+    ]))
+}
+
+fn render(value: Value) -> String {
+    // Every field we send through here is constructed from UTF-8 strings, so
+    // the bencoded bytes are always valid UTF-8 too.
+    String::from_utf8(value.encode()).expect("bencoded request was not valid utf8")
+}
+
+/// Opens a throwaway connection to `addr`, sends a `clone` op and returns the
+/// resulting session id. Called once up front so the long-lived eval,
+/// go-to-definition and completion sockets can all join the same session.
+pub fn clone_session(addr: SocketAddr) -> Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&clone_op().encode())?;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        let n = stream.read(&mut chunk)?;
+        buf.extend_from_slice(&chunk[..n]);
+
+        if let Ok((value, _)) = ::bencode::decode(&buf) {
+            return value
+                .get("new-session")
+                .and_then(Value::as_str)
+                .map(|s| s.to_owned())
+                .ok_or_else(|| {
+                    ::result::error(::failure::err_msg("clone op did not return a session id"))
+                });
+        }
+
+        if n == 0 {
+            return Err(::result::error(::failure::err_msg(
+                "connection closed before a session id was received",
+            )));
+        }
+    }
+}
+
+/// Which op a socket was opened to drive, so a decoded reply can be mapped
+/// onto the right `Response` shape.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eval,
+    Info,
+    Complete,
+}
+
+/// Maps a single decoded nREPL response message onto the same `Response`
+/// variants the prepl transport produces. `None` means the message carries
+/// no payload relevant to `op` (e.g. a bare `status` frame).
+pub fn to_response(op: Op, value: &Value) -> Option<Response> {
+    match op {
+        Op::Eval => eval_response(value),
+        Op::Info => info_response(value),
+        Op::Complete => complete_response(value),
+    }
+}
+
+fn eval_response(value: &Value) -> Option<Response> {
+    if let Some(value) = value.get("value").and_then(Value::as_str) {
+        return Some(Response::Ret(value.to_owned(), 0));
+    }
+
+    if let Some(out) = value.get("out").and_then(Value::as_str) {
+        return Some(Response::Out(out.to_owned()));
+    }
+
+    if let Some(err) = value.get("err").and_then(Value::as_str) {
+        return Some(Response::Err(err.to_owned()));
+    }
+
+    if let Some(ex) = value.get("ex").and_then(Value::as_str) {
+        return Some(Response::Err(ex.to_owned()));
+    }
+
+    None
+}
+
+/// An `info` reply carries `file`/`line`/`column` instead of `value`.
+/// Re-renders it as the `["file" line column]` EDN shape `util::parse_location`
+/// already knows how to read off the prepl transport.
+fn info_response(value: &Value) -> Option<Response> {
+    let file = value.get("file").and_then(Value::as_str)?;
+    let line = value.get("line").and_then(Value::as_int)?;
+    let column = value.get("column").and_then(Value::as_int).unwrap_or(0);
+
+    Some(Response::Ret(
+        format!("[\"{}\" {} {}]", file, line, column),
+        0,
+    ))
+}
+
+/// A `complete` reply carries a `completions` list of candidate dicts.
+/// Re-renders it as the `("candidate" ...)` EDN list `util::parse_completions`
+/// already knows how to read off the prepl transport.
+fn complete_response(value: &Value) -> Option<Response> {
+    let completions = value.get("completions").and_then(Value::as_list)?;
+
+    let candidates: Vec<String> = completions
+        .iter()
+        .filter_map(|entry| entry.get("candidate").and_then(Value::as_str))
+        .map(|candidate| format!("\"{}\"", candidate))
+        .collect();
+
+    Some(Response::Ret(format!("({})", candidates.join(" ")), 0))
+}
+
+/// True once a response's `status` list includes `"done"`, marking the end
+/// of a single nREPL reply.
+pub fn is_done(value: &Value) -> bool {
+    value
+        .get("status")
+        .and_then(Value::as_list)
+        .map(|statuses| {
+            statuses
+                .iter()
+                .any(|status| status.as_str() == Some("done"))
+        })
+        .unwrap_or(false)
+}
+
+/// One step of decoding `buf` as nREPL replies for `op`.
+pub enum Decoded {
+    /// A reply `to_response` maps to something worth surfacing, plus how
+    /// many bytes of `buf` it consumed.
+    Response(Response, usize),
+    /// A reply `to_response` has nothing to map (e.g. a bare ack), but
+    /// `"done"` hasn't closed out the request yet.
+    Skipped(usize),
+    /// The `"done"` status closed out the request.
+    Done(usize),
+    /// `buf` doesn't yet hold a full bencoded value.
+    Incomplete,
+}
+
+/// Decodes one bencoded value off the front of `buf` and classifies it via
+/// `to_response`/`is_done`. This is the nREPL transport's entire receive
+/// path: a socket reader accumulates bytes into `buf` and calls this in a
+/// loop, feeding the consumed count back in, until it gets a `Response` or
+/// `Done`.
+pub fn decode_response(op: Op, buf: &[u8]) -> Decoded {
+    let (value, consumed) = match ::bencode::decode(buf) {
+        Ok(decoded) => decoded,
+        Err(_) => return Decoded::Incomplete,
+    };
+
+    if let Some(response) = to_response(op, &value) {
+        return Decoded::Response(response, consumed);
+    }
+
+    if is_done(&value) {
+        Decoded::Done(consumed)
+    } else {
+        Decoded::Skipped(consumed)
+    }
+}
@@ -1,24 +1,112 @@
 use clojure;
+use discovery::Discovered;
 use editor::{Context, Server};
+use nrepl;
 use regex::Regex;
 use repl::{Client, Response};
 use result::{error, Result};
-use std::collections::{hash_map, HashMap};
+use std::collections::{hash_map, HashMap, VecDeque};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use util;
 
+/// Which wire protocol to speak when connecting, as chosen by the caller
+/// (typically from how the REPL was discovered).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Prepl,
+    NRepl,
+}
+
+/// Selects which wire protocol a `Connection`'s sockets speak. Leiningen and
+/// deps.edn sockets generally speak raw prepl; Leiningen/deps/shadow-cljs
+/// *nREPL* servers need the bencode-based nREPL transport instead, with all
+/// requests tagged with the session id obtained from an initial `clone` op.
+#[derive(Debug, Clone)]
+pub enum Protocol {
+    Prepl,
+    NRepl { session: String },
+}
+
+/// How many consecutive missed heartbeats it takes before a connection is
+/// considered dead and reconnection kicks in.
+const HEARTBEAT_MAX_MISSES: u32 = 3;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Lifecycle state of a `Connection`, surfaced to the editor through
+/// `Server` log lines whenever it changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Health {
+    Connected,
+    Reconnecting,
+    Dead,
+}
+
+/// How many forms a `Connection` keeps in `history` before the oldest ones
+/// are dropped.
+const HISTORY_CAP: usize = 50;
+
+/// The eventual result of a submitted form, once its response arrives.
+#[derive(Debug, Clone)]
+pub enum EvalOutcome {
+    Ret(String),
+    Err(String),
+}
+
+/// A single submitted form, recorded in `Connection::history` at write time
+/// and filled in with its result once the matching response arrives.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    id: usize,
+    pub code: String,
+    pub ns: String,
+    pub at: Instant,
+    pub result: Option<EvalOutcome>,
+}
+
+/// A REPL connection's sockets, wrapped so a background heartbeat thread can
+/// swap them out in place on reconnect without the rest of the pool needing
+/// to know a reconnect ever happened.
 #[derive(Debug)]
-pub struct Connection {
+struct Sockets {
     eval: Client,
     go_to_definition: Client,
     completions: Client,
+    documentation: Client,
+    source: Client,
+    apropos: Client,
+    /// A socket of its own, so the keepalive ping/pong never shares a reply
+    /// stream with a real eval and can't be mistaken for one.
+    heartbeat: Client,
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    sockets: Arc<Mutex<Sockets>>,
+    health: Arc<Mutex<Health>>,
+    protocol: Arc<Mutex<Protocol>>,
+    history: Arc<Mutex<VecDeque<HistoryEntry>>>,
+    /// Ids of evals submitted but not yet resolved, oldest first. The eval
+    /// response loop pops the front on each `Ret`/`Err` to know which
+    /// `history` entry that reply belongs to, instead of guessing from
+    /// whichever entry happens to still be unfilled.
+    pending: Arc<Mutex<VecDeque<usize>>>,
+    /// Set by `Drop` to stop the detached heartbeat thread from outliving
+    /// its `Connection`.
+    stop: Arc<AtomicBool>,
 
     pub user_ns: String,
     pub core_ns: String,
     pub addr: SocketAddr,
     pub expr: Regex,
     pub lang: clojure::Lang,
+    pub transport: Transport,
 }
 
 #[derive(Debug, Fail)]
@@ -28,14 +116,314 @@ enum Error {
 
     #[fail(display = "no matching connections for path: {}", path)]
     NoMatchingConnections { path: String },
+
+    #[fail(display = "no history entry {} for connection: {}", index, key)]
+    HistoryIndexMissing { key: String, index: usize },
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Generates an id unique within this process, used both to tag nREPL
+/// requests (`"id"`) and, for evals, to correlate a `HistoryEntry` with the
+/// response it's actually waiting for.
+fn next_submission_id() -> usize {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+fn next_id() -> String {
+    next_submission_id().to_string()
+}
+
+/// True if `err` looks like the socket itself is gone (broken pipe,
+/// connection reset/aborted, or EOF) rather than some transient or
+/// encoding problem with this one write. Only errors like this warrant
+/// dropping the connection from the pool — anything else just fails this
+/// one eval.
+fn is_broken_pipe(err: &::failure::Error) -> bool {
+    err.downcast_ref::<::std::io::Error>()
+        .map(|io_err| {
+            use std::io::ErrorKind::*;
+            match io_err.kind() {
+                BrokenPipe | ConnectionReset | ConnectionAborted | NotConnected
+                | UnexpectedEof => true,
+                _ => false,
+            }
+        })
+        .unwrap_or(false)
+}
+
+fn connect_protocol(addr: SocketAddr, transport: Transport) -> Result<Protocol> {
+    Ok(match transport {
+        Transport::Prepl => Protocol::Prepl,
+        Transport::NRepl => Protocol::NRepl {
+            session: nrepl::clone_session(addr)?,
+        },
+    })
+}
+
+fn connect_sockets(addr: SocketAddr, protocol: &Protocol) -> Result<Sockets> {
+    Ok(Sockets {
+        eval: Client::connect(addr, protocol.clone(), nrepl::Op::Eval)?,
+        go_to_definition: Client::connect(addr, protocol.clone(), nrepl::Op::Info)?,
+        completions: Client::connect(addr, protocol.clone(), nrepl::Op::Complete)?,
+        documentation: Client::connect(addr, protocol.clone(), nrepl::Op::Eval)?,
+        source: Client::connect(addr, protocol.clone(), nrepl::Op::Eval)?,
+        apropos: Client::connect(addr, protocol.clone(), nrepl::Op::Eval)?,
+        heartbeat: Client::connect(addr, protocol.clone(), nrepl::Op::Eval)?,
+    })
+}
+
+/// Writes `ping` to a throwaway clone of the heartbeat socket and waits for
+/// its reply. A private socket means there's no real eval to confuse it
+/// with, so any `Ret` at all counts as alive.
+fn probe_heartbeat(mut heartbeat: Client, ping: String) -> bool {
+    if heartbeat.write(&ping).is_err() {
+        return false;
+    }
+
+    match heartbeat.responses() {
+        Ok(mut responses) => matches!(responses.next(), Some(Ok(Response::Ret(_, _)))),
+        Err(_) => false,
+    }
+}
+
+/// Renders a form to evaluate as the wire-format appropriate for `protocol`.
+/// Free function so the heartbeat, which only has a protocol snapshot
+/// rather than a whole `Connection`, can call it too.
+fn render_eval_with(protocol: &Protocol, lang: clojure::Lang, code: &str, ns: &str) -> String {
+    render_eval_with_id(protocol, lang, code, ns, &next_id())
+}
+
+/// Same as `render_eval_with`, but with the nREPL request id supplied by the
+/// caller so it can double as the id a `HistoryEntry` waits on.
+fn render_eval_with_id(
+    protocol: &Protocol,
+    lang: clojure::Lang,
+    code: &str,
+    ns: &str,
+    id: &str,
+) -> String {
+    match protocol {
+        Protocol::Prepl => clojure::eval(code, ns, &lang),
+        Protocol::NRepl { session } => nrepl::eval_op(code, ns, session, id),
+    }
+}
+
+/// (Re-)spawns the six per-socket response loops and bootstraps the eval
+/// socket. Used both by `Connection::start_response_loops` on first connect
+/// and by the heartbeat's reconnect arm, which needs to get readers running
+/// again on freshly swapped-in sockets.
+fn spawn_response_loops(
+    sockets: &Sockets,
+    key: &str,
+    server: &Server,
+    protocol: &Protocol,
+    lang: clojure::Lang,
+    user_ns: &str,
+    history: Arc<Mutex<VecDeque<HistoryEntry>>>,
+    pending: Arc<Mutex<VecDeque<usize>>>,
+) -> Result<()> {
+    let mut eval = sockets.eval.try_clone()?;
+    let mut eval_server = server.clone();
+    let eval_key = key.to_string();
+
+    eval.write(&render_eval_with(protocol, lang, &clojure::bootstrap(), user_ns))?;
+
+    thread::spawn(move || {
+        let log = |server: &mut Server, tag_suffix: &str, line_prefix: &str, msg: String| {
+            let lines: Vec<String> = msg
+                .split('\n')
+                .map(|line| format!("{}{}", line_prefix, line))
+                .collect();
+
+            server.log_writelns(&format!("{} {}", eval_key, tag_suffix), &lines);
+        };
+
+        let record = |history: &Mutex<VecDeque<HistoryEntry>>,
+                      pending: &Mutex<VecDeque<usize>>,
+                      outcome: EvalOutcome| {
+            if let Some(id) = pending.lock().expect("pending lock poisoned").pop_front() {
+                if let Some(entry) = history
+                    .lock()
+                    .expect("history lock poisoned")
+                    .iter_mut()
+                    .find(|entry| entry.id == id)
+                {
+                    entry.result = Some(outcome);
+                }
+            }
+        };
+
+        for response in eval.responses().expect("couldn't get responses") {
+            match response {
+                Ok(Response::Ret(msg, ms)) => {
+                    record(&history, &pending, EvalOutcome::Ret(msg.clone()));
+                    log(&mut eval_server, &format!("ret {}ms", ms), "", msg)
+                }
+                Ok(Response::Tap(msg, ms)) => {
+                    log(&mut eval_server, &format!("tap {}ms", ms), "", msg)
+                }
+                Ok(Response::Out(msg)) => log(&mut eval_server, "out", ";; ", msg),
+                Ok(Response::Err(msg)) => {
+                    record(&history, &pending, EvalOutcome::Err(msg.clone()));
+                    log(&mut eval_server, "err", ";; ", msg)
+                }
+
+                Err(msg) => eval_server.err_writeln(&format!("Error from eval connection: {}", msg)),
+            }
+        }
+    });
+
+    let go_to_definition = sockets.go_to_definition.try_clone()?;
+    let mut go_to_definition_server = server.clone();
+
+    thread::spawn(move || {
+        for response in go_to_definition
+            .responses()
+            .expect("couldn't get responses")
+        {
+            match response {
+                Ok(Response::Ret(msg, _)) => {
+                    if let Some(loc) = util::parse_location(&msg) {
+                        if let Err(msg) = go_to_definition_server.go_to(loc) {
+                            go_to_definition_server
+                                .err_writeln(&format!("Error while going to definition: {}", msg))
+                        }
+                    } else if msg == ":unknown" {
+                        go_to_definition_server.err_writeln("Location unknown");
+                    }
+                }
+                Ok(Response::Err(msg)) => error!("Error message from go to location: {}", msg),
+                Ok(Response::Tap(_, _)) => (),
+                Ok(Response::Out(_)) => (),
+
+                Err(msg) => go_to_definition_server
+                    .err_writeln(&format!("Error from definition connection: {}", msg)),
+            }
+        }
+    });
+
+    let completions = sockets.completions.try_clone()?;
+    let mut completions_server = server.clone();
+
+    thread::spawn(move || {
+        for response in completions.responses().expect("couldn't get responses") {
+            match response {
+                Ok(Response::Ret(msg, _)) => {
+                    if let Some(completions) = util::parse_completions(&msg) {
+                        info!("Updating {} completions!", completions.len());
+
+                        if let Err(msg) = completions_server.update_completions(&completions) {
+                            completions_server
+                                .err_writeln(&format!("Error while completing: {}", msg))
+                        }
+                    }
+                }
+                Ok(Response::Err(msg)) => error!("Error message from completions: {}", msg),
+                Ok(Response::Tap(_, _)) => (),
+                Ok(Response::Out(_)) => (),
+
+                Err(msg) => completions_server
+                    .err_writeln(&format!("Error from completion connection: {}", msg)),
+            }
+        }
+    });
+
+    let documentation = sockets.documentation.try_clone()?;
+    let mut documentation_server = server.clone();
+    let documentation_key = key.to_string();
+
+    thread::spawn(move || {
+        for response in documentation
+            .responses()
+            .expect("couldn't get responses")
+        {
+            match response {
+                // `clojure.repl/doc` prints to stdout rather than returning
+                // a value, so its output typically arrives as `Out` rather
+                // than `Ret` — handle both the same way.
+                Ok(Response::Ret(msg, _)) | Ok(Response::Out(msg)) => {
+                    let lines: Vec<String> = msg.split('\n').map(str::to_owned).collect();
+                    documentation_server
+                        .log_writelns(&format!("{} doc", documentation_key), &lines);
+                }
+                Ok(Response::Err(msg)) => error!("Error message from documentation: {}", msg),
+                Ok(Response::Tap(_, _)) => (),
+
+                Err(msg) => documentation_server
+                    .err_writeln(&format!("Error from documentation connection: {}", msg)),
+            }
+        }
+    });
+
+    let source = sockets.source.try_clone()?;
+    let mut source_server = server.clone();
+    let source_key = key.to_string();
+
+    thread::spawn(move || {
+        for response in source.responses().expect("couldn't get responses") {
+            match response {
+                // `clojure.repl/source` prints to stdout rather than
+                // returning a value, so its output typically arrives as
+                // `Out` rather than `Ret` — handle both the same way.
+                Ok(Response::Ret(msg, _)) | Ok(Response::Out(msg)) => {
+                    let lines: Vec<String> = msg.split('\n').map(str::to_owned).collect();
+                    source_server.log_writelns(&format!("{} source", source_key), &lines);
+                }
+                Ok(Response::Err(msg)) => error!("Error message from source: {}", msg),
+                Ok(Response::Tap(_, _)) => (),
+
+                Err(msg) => {
+                    source_server.err_writeln(&format!("Error from source connection: {}", msg))
+                }
+            }
+        }
+    });
+
+    let apropos = sockets.apropos.try_clone()?;
+    let mut apropos_server = server.clone();
+    let apropos_key = key.to_string();
+
+    thread::spawn(move || {
+        for response in apropos.responses().expect("couldn't get responses") {
+            match response {
+                // `clojure.repl/apropos` returns a value directly, but treat
+                // `Out` the same as `Ret` for consistency with documentation
+                // and source, in case a matcher ever prints instead.
+                Ok(Response::Ret(msg, _)) | Ok(Response::Out(msg)) => {
+                    let lines: Vec<String> = msg.split('\n').map(str::to_owned).collect();
+                    apropos_server.log_writelns(&format!("{} apropos", apropos_key), &lines);
+                }
+                Ok(Response::Err(msg)) => error!("Error message from apropos: {}", msg),
+                Ok(Response::Tap(_, _)) => (),
+
+                Err(msg) => {
+                    apropos_server.err_writeln(&format!("Error from apropos connection: {}", msg))
+                }
+            }
+        }
+    });
+
+    Ok(())
 }
 
 impl Connection {
-    pub fn connect(addr: SocketAddr, expr: Regex, lang: clojure::Lang) -> Result<Self> {
+    pub fn connect(
+        addr: SocketAddr,
+        expr: Regex,
+        lang: clojure::Lang,
+        transport: Transport,
+    ) -> Result<Self> {
+        let protocol = connect_protocol(addr, transport)?;
+        let sockets = connect_sockets(addr, &protocol)?;
+
         Ok(Self {
-            eval: Client::connect(addr)?,
-            go_to_definition: Client::connect(addr)?,
-            completions: Client::connect(addr)?,
+            sockets: Arc::new(Mutex::new(sockets)),
+            health: Arc::new(Mutex::new(Health::Connected)),
+            protocol: Arc::new(Mutex::new(protocol)),
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(HISTORY_CAP))),
+            pending: Arc::new(Mutex::new(VecDeque::new())),
+            stop: Arc::new(AtomicBool::new(false)),
 
             user_ns: match lang {
                 clojure::Lang::Clojure => "user".to_owned(),
@@ -48,112 +436,288 @@ impl Connection {
             addr,
             expr,
             lang,
+            transport,
         })
     }
 
-    pub fn start_response_loops(&self, key: &str, server: &Server) -> Result<()> {
-        let mut eval = self.eval.try_clone()?;
-        let mut eval_server = server.clone();
-        let eval_key = key.to_string();
+    pub fn health(&self) -> Health {
+        *self.health.lock().expect("health lock poisoned")
+    }
 
-        eval.write(&clojure::eval(
-            &clojure::bootstrap(),
-            &self.user_ns,
-            &self.lang,
-        ))?;
+    /// Renders a form to evaluate as the wire-format appropriate for this
+    /// connection's protocol.
+    fn render_eval(&self, code: &str, ns: &str) -> String {
+        let protocol = self.protocol.lock().expect("protocol lock poisoned").clone();
+        render_eval_with(&protocol, self.lang, code, ns)
+    }
 
-        thread::spawn(move || {
-            let log = |server: &mut Server, tag_suffix: &str, line_prefix: &str, msg: String| {
-                let lines: Vec<String> = msg
-                    .split('\n')
-                    .map(|line| format!("{}{}", line_prefix, line))
-                    .collect();
-
-                server.log_writelns(&format!("{} {}", eval_key, tag_suffix), &lines);
-            };
-
-            for response in eval.responses().expect("couldn't get responses") {
-                match response {
-                    Ok(Response::Ret(msg, ms)) => {
-                        log(&mut eval_server, &format!("ret {}ms", ms), "", msg)
-                    }
-                    Ok(Response::Tap(msg, ms)) => {
-                        log(&mut eval_server, &format!("tap {}ms", ms), "", msg)
-                    }
-                    Ok(Response::Out(msg)) => log(&mut eval_server, "out", ";; ", msg),
-                    Ok(Response::Err(msg)) => log(&mut eval_server, "err", ";; ", msg),
+    /// Renders a go-to-definition request. Prepl drives this through an
+    /// evaluated form; nREPL has a dedicated `info` op instead.
+    fn render_definition(&self, name: &str, ns: &str) -> String {
+        match &*self.protocol.lock().expect("protocol lock poisoned") {
+            Protocol::Prepl => clojure::eval(&clojure::definition(name), ns, &self.lang),
+            Protocol::NRepl { session } => nrepl::info_op(name, ns, session, &next_id()),
+        }
+    }
 
-                    Err(msg) => {
-                        eval_server.err_writeln(&format!("Error from eval connection: {}", msg))
-                    }
-                }
+    /// Renders a completion request for the symbol prefix under the cursor.
+    /// Prepl drives this through an evaluated form that returns every
+    /// candidate in `ns` regardless of `prefix` (the editor filters
+    /// client-side); nREPL's dedicated `complete` op filters server-side, so
+    /// `prefix` is passed through to it directly.
+    fn render_completions(&self, prefix: &str, ns: &str) -> String {
+        match &*self.protocol.lock().expect("protocol lock poisoned") {
+            Protocol::Prepl => {
+                clojure::eval(&clojure::completions(ns, &self.core_ns), ns, &self.lang)
             }
+            Protocol::NRepl { session } => nrepl::complete_op(prefix, ns, session, &next_id()),
+        }
+    }
+
+    pub fn write_eval(&self, code: &str) -> Result<()> {
+        let ns = self.user_ns.clone();
+        self.write_eval_ns(code, &ns)
+    }
+
+    pub fn write_eval_ns(&self, code: &str, ns: &str) -> Result<()> {
+        let id = next_submission_id();
+        let protocol = self.protocol.lock().expect("protocol lock poisoned").clone();
+        let rendered = render_eval_with_id(&protocol, self.lang, code, ns, &id.to_string());
+
+        self.sockets
+            .lock()
+            .expect("sockets lock poisoned")
+            .eval
+            .write(&rendered)?;
+
+        let mut history = self.history.lock().expect("history lock poisoned");
+        if history.len() >= HISTORY_CAP {
+            history.pop_front();
+        }
+        history.push_back(HistoryEntry {
+            id,
+            code: code.to_owned(),
+            ns: ns.to_owned(),
+            at: Instant::now(),
+            result: None,
         });
+        self.pending.lock().expect("pending lock poisoned").push_back(id);
+
+        Ok(())
+    }
+
+    /// Returns the most recent `limit` history entries, oldest first.
+    pub fn history(&self, limit: usize) -> Vec<HistoryEntry> {
+        let history = self.history.lock().expect("history lock poisoned");
+        history
+            .iter()
+            .rev()
+            .take(limit)
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// Looks up a history entry by its index from the most recent end (`0`
+    /// is the last submitted form).
+    pub fn history_entry(&self, index_from_end: usize) -> Option<HistoryEntry> {
+        let history = self.history.lock().expect("history lock poisoned");
+        history.iter().rev().nth(index_from_end).cloned()
+    }
+
+    /// The result of the last submitted form, if it has come back yet.
+    pub fn last_result(&self) -> Option<EvalOutcome> {
+        let history = self.history.lock().expect("history lock poisoned");
+        history.back().and_then(|entry| entry.result.clone())
+    }
+
+    pub fn write_definition(&self, name: &str, ns: &str) -> Result<()> {
+        let rendered = self.render_definition(name, ns);
+        self.sockets
+            .lock()
+            .expect("sockets lock poisoned")
+            .go_to_definition
+            .write(&rendered)
+    }
+
+    pub fn write_completions(&self, prefix: &str, ns: &str) -> Result<()> {
+        let rendered = self.render_completions(prefix, ns);
+        self.sockets
+            .lock()
+            .expect("sockets lock poisoned")
+            .completions
+            .write(&rendered)
+    }
 
-        let go_to_definition = self.go_to_definition.try_clone()?;
-        let mut go_to_definition_server = server.clone();
+    pub fn write_documentation(&self, name: &str, ns: &str) -> Result<()> {
+        let rendered = self.render_eval(&clojure::documentation(name), ns);
+        self.sockets
+            .lock()
+            .expect("sockets lock poisoned")
+            .documentation
+            .write(&rendered)
+    }
+
+    pub fn write_source(&self, name: &str, ns: &str) -> Result<()> {
+        let rendered = self.render_eval(&clojure::source(name), ns);
+        self.sockets
+            .lock()
+            .expect("sockets lock poisoned")
+            .source
+            .write(&rendered)
+    }
+
+    pub fn write_apropos(&self, pattern: &str, ns: &str) -> Result<()> {
+        let rendered = self.render_eval(&clojure::apropos(pattern), ns);
+        self.sockets
+            .lock()
+            .expect("sockets lock poisoned")
+            .apropos
+            .write(&rendered)
+    }
+
+    pub fn start_response_loops(&self, key: &str, server: &Server) -> Result<()> {
+        let sockets = self.sockets.lock().expect("sockets lock poisoned");
+        let protocol = self.protocol.lock().expect("protocol lock poisoned").clone();
+
+        spawn_response_loops(
+            &sockets,
+            key,
+            server,
+            &protocol,
+            self.lang,
+            &self.user_ns,
+            self.history.clone(),
+            self.pending.clone(),
+        )
+    }
+
+    /// Spawns the background keepalive loop: periodically pings a dedicated
+    /// heartbeat socket on its own throwaway clone and waits up to
+    /// `HEARTBEAT_TIMEOUT` for a reply. After `HEARTBEAT_MAX_MISSES`
+    /// consecutive misses the connection is marked `Health::Reconnecting` and
+    /// reconnected with
+    /// exponential backoff, re-running `spawn_response_loops` and
+    /// re-bootstrapping once a new socket is up.
+    pub fn start_heartbeat(&self, key: &str, server: &Server) {
+        let sockets = self.sockets.clone();
+        let health = self.health.clone();
+        let protocol_cell = self.protocol.clone();
+        let history = self.history.clone();
+        let pending = self.pending.clone();
+        let stop = self.stop.clone();
+        let mut heartbeat_server = server.clone();
+        let key = key.to_string();
+
+        let addr = self.addr;
+        let lang = self.lang;
+        let transport = self.transport;
+        let user_ns = self.user_ns.clone();
 
         thread::spawn(move || {
-            for response in go_to_definition
-                .responses()
-                .expect("couldn't get responses")
-            {
-                match response {
-                    Ok(Response::Ret(msg, _)) => {
-                        if let Some(loc) = util::parse_location(&msg) {
-                            if let Err(msg) = go_to_definition_server.go_to(loc) {
-                                go_to_definition_server.err_writeln(&format!(
-                                    "Error while going to definition: {}",
-                                    msg
-                                ))
-                            }
-                        } else if msg == ":unknown" {
-                            go_to_definition_server.err_writeln("Location unknown");
-                        }
+            let mut misses = 0;
+
+            while !stop.load(Ordering::Relaxed) {
+                thread::sleep(HEARTBEAT_INTERVAL);
+
+                let protocol = protocol_cell.lock().expect("protocol lock poisoned").clone();
+                let ping = render_eval_with(&protocol, lang, "42", &user_ns);
+                let probe = sockets
+                    .lock()
+                    .expect("sockets lock poisoned")
+                    .heartbeat
+                    .try_clone();
+
+                let alive = match probe {
+                    Ok(heartbeat) => {
+                        let (tx, rx) = mpsc::channel();
+                        thread::spawn(move || {
+                            let _ = tx.send(probe_heartbeat(heartbeat, ping));
+                        });
+                        rx.recv_timeout(HEARTBEAT_TIMEOUT).unwrap_or(false)
                     }
-                    Ok(Response::Err(msg)) => error!("Error message from go to location: {}", msg),
-                    Ok(Response::Tap(_, _)) => (),
-                    Ok(Response::Out(_)) => (),
+                    Err(_) => false,
+                };
 
-                    Err(msg) => go_to_definition_server
-                        .err_writeln(&format!("Error from definition connection: {}", msg)),
+                if alive {
+                    misses = 0;
+                    continue;
                 }
-            }
-        });
 
-        let completions = self.completions.try_clone()?;
-        let mut completions_server = server.clone();
+                misses += 1;
+                if misses < HEARTBEAT_MAX_MISSES {
+                    continue;
+                }
 
-        thread::spawn(move || {
-            for response in completions.responses().expect("couldn't get responses") {
-                match response {
-                    Ok(Response::Ret(msg, _)) => {
-                        if let Some(completions) = util::parse_completions(&msg) {
-                            info!("Updating {} completions!", completions.len());
-
-                            if let Err(msg) = completions_server.update_completions(&completions) {
-                                completions_server
-                                    .err_writeln(&format!("Error while completing: {}", msg))
+                *health.lock().expect("health lock poisoned") = Health::Reconnecting;
+                heartbeat_server.log_writelns(
+                    "heartbeat",
+                    &[format!("{} reconnecting: missed {} heartbeats", key, misses)],
+                );
+
+                let mut backoff = RECONNECT_BACKOFF_MIN;
+                while !stop.load(Ordering::Relaxed) {
+                    match connect_protocol(addr, transport)
+                        .and_then(|protocol| connect_sockets(addr, &protocol).map(|s| (protocol, s)))
+                    {
+                        Ok((protocol, new_sockets)) => {
+                            let mut guard = sockets.lock().expect("sockets lock poisoned");
+                            *guard = new_sockets;
+
+                            if let Err(msg) = spawn_response_loops(
+                                &guard,
+                                &key,
+                                &heartbeat_server,
+                                &protocol,
+                                lang,
+                                &user_ns,
+                                history.clone(),
+                                pending.clone(),
+                            ) {
+                                heartbeat_server.err_writeln(&format!(
+                                    "{} reconnected but failed to restart response loops: {}",
+                                    key, msg
+                                ));
                             }
+                            drop(guard);
+
+                            *protocol_cell.lock().expect("protocol lock poisoned") = protocol;
+                            *health.lock().expect("health lock poisoned") = Health::Connected;
+                            misses = 0;
+
+                            heartbeat_server
+                                .log_writelns("heartbeat", &[format!("{} reconnected", key)]);
+                            break;
+                        }
+                        Err(msg) => {
+                            heartbeat_server.err_writeln(&format!(
+                                "{} reconnect attempt failed: {}",
+                                key, msg
+                            ));
+                            thread::sleep(backoff);
+                            backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
                         }
                     }
-                    Ok(Response::Err(msg)) => error!("Error message from completions: {}", msg),
-                    Ok(Response::Tap(_, _)) => (),
-                    Ok(Response::Out(_)) => (),
-
-                    Err(msg) => completions_server
-                        .err_writeln(&format!("Error from completion connection: {}", msg)),
                 }
             }
         });
-
-        Ok(())
     }
 }
 
 impl Drop for Connection {
     fn drop(&mut self) {
-        if let Err(msg) = self.eval.quit() {
+        // Signal the detached heartbeat thread to stop before anything
+        // else: once this `Connection` is gone there must be nothing left
+        // reconnecting or keeping it alive in the background.
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Err(msg) = self
+            .sockets
+            .lock()
+            .expect("sockets lock poisoned")
+            .eval
+            .quit()
+        {
             error!("Failed to quit REPL cleanly: {}", msg);
         }
     }
@@ -186,10 +750,13 @@ impl Pool {
         addr: SocketAddr,
         expr: &Regex,
         lang: clojure::Lang,
+        transport: Transport,
     ) -> Result<()> {
-        Connection::connect(addr, expr.clone(), lang)
+        Connection::connect(addr, expr.clone(), lang, transport)
             .and_then(|conn| {
-                conn.start_response_loops(&format!("[{}]", key), server)?;
+                let full_key = format!("[{}]", key);
+                conn.start_response_loops(&full_key, server)?;
+                conn.start_heartbeat(&full_key, server);
                 Ok(conn)
             })
             .map(|conn| {
@@ -208,32 +775,103 @@ impl Pool {
         }
     }
 
+    /// Reconciles the pool against a fresh `discovery::scan`/`Watcher`
+    /// result: connects anything newly discovered and disconnects anything
+    /// that's disappeared (its port file was removed or rewritten to a port
+    /// we haven't connected to yet). Only touches connections this function
+    /// itself created, keyed by the `auto:` prefix, so it never steps on
+    /// connections set up explicitly through `connect`.
+    pub fn sync_discovered(&mut self, discovered: &[Discovered], server: &Server) -> Result<()> {
+        let stale: Vec<String> = self
+            .conns
+            .iter()
+            .filter(|(key, conn)| {
+                key.starts_with("auto:")
+                    && !discovered.iter().any(|repl| {
+                        **key == format!("auto:{}", repl.key) && repl.addr == conn.addr
+                    })
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in stale {
+            info!("Discovered REPL {} disappeared, disconnecting", key);
+            self.conns.remove(&key);
+        }
+
+        for repl in discovered {
+            let key = format!("auto:{}", repl.key);
+            if !self.conns.contains_key(&key) {
+                info!("Discovered REPL at {}, connecting", repl.addr);
+                self.connect(
+                    &key,
+                    server,
+                    repl.addr,
+                    &repl.expr,
+                    repl.lang,
+                    Transport::NRepl,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Broadcasts `code` to every connection matching `ctx.path`. A write
+    /// failure on one connection doesn't abort the others: it is logged, and
+    /// if it looks like the socket is gone (see `is_broken_pipe`) the
+    /// connection is also pruned from the pool. Evaluation is considered a
+    /// success as long as at least one matching connection accepted the
+    /// write.
     pub fn eval(&mut self, code: &str, ctx: Context) -> Result<Vec<String>> {
-        let mut matches = self
+        let keys: Vec<String> = self
             .conns
-            .iter_mut()
+            .iter()
             .filter(|(_, conn)| conn.expr.is_match(&ctx.path))
-            .peekable();
-
-        let mut names = vec![];
+            .map(|(key, _)| key.clone())
+            .collect();
 
-        if matches.peek().is_some() {
-            for (name, conn) in matches {
-                info!("Evaluating through: {:?}", conn);
-                conn.eval.write(&clojure::eval(
-                    code,
-                    &ctx.ns.clone().unwrap_or(conn.user_ns.clone()),
-                    &conn.lang,
-                ))?;
+        if keys.is_empty() {
+            return Err(error(Error::NoMatchingConnections {
+                path: ctx.path.clone(),
+            }));
+        }
 
-                names.push(name.clone());
+        let mut evaluated = vec![];
+        let mut dropped = vec![];
+
+        for key in keys {
+            let conn = self
+                .conns
+                .get(&key)
+                .expect("key came from self.conns, so it must still be present");
+
+            info!("Evaluating through: {:?}", conn);
+            let ns = ctx.ns.clone().unwrap_or(conn.user_ns.clone());
+
+            match conn.write_eval_ns(code, &ns) {
+                Ok(()) => evaluated.push(key),
+                Err(msg) => {
+                    if is_broken_pipe(&msg) {
+                        error!("Dropping connection [{}], write failed: {}", key, msg);
+                        dropped.push(key);
+                    } else {
+                        error!("Eval through [{}] failed: {}", key, msg);
+                    }
+                }
             }
+        }
 
-            Ok(names)
-        } else {
+        for key in &dropped {
+            self.conns.remove(key);
+        }
+
+        if evaluated.is_empty() {
             Err(error(Error::NoMatchingConnections {
                 path: ctx.path.clone(),
             }))
+        } else {
+            Ok(evaluated)
         }
     }
 
@@ -244,11 +882,8 @@ impl Pool {
             .find(|(_, conn)| conn.expr.is_match(&ctx.path))
         {
             info!("Looking up definition through: {:?}", conn);
-            conn.go_to_definition.write(&clojure::eval(
-                &clojure::definition(&name),
-                &ctx.ns.unwrap_or(conn.user_ns.clone()),
-                &conn.lang,
-            ))?;
+            let ns = ctx.ns.unwrap_or(conn.user_ns.clone());
+            conn.write_definition(&name, &ns)?;
 
             Ok(())
         } else {
@@ -258,20 +893,121 @@ impl Pool {
         }
     }
 
-    pub fn update_completions(&mut self, ctx: Context) -> Result<()> {
+    pub fn update_completions(&mut self, prefix: &str, ctx: Context) -> Result<()> {
         if let Some((_, conn)) = self
             .conns
             .iter_mut()
             .find(|(_, conn)| conn.expr.is_match(&ctx.path))
         {
-            let ns = &ctx.ns.unwrap_or(conn.user_ns.clone());
-            conn.completions.write(&clojure::eval(
-                &clojure::completions(&ns, &conn.core_ns),
-                ns,
-                &conn.lang,
-            ))?;
+            let ns = ctx.ns.unwrap_or(conn.user_ns.clone());
+            conn.write_completions(prefix, &ns)?;
         }
 
         Ok(())
     }
+
+    pub fn documentation(&mut self, name: &str, ctx: Context) -> Result<()> {
+        if let Some((_, conn)) = self
+            .conns
+            .iter_mut()
+            .find(|(_, conn)| conn.expr.is_match(&ctx.path))
+        {
+            info!("Looking up documentation through: {:?}", conn);
+            let ns = ctx.ns.unwrap_or(conn.user_ns.clone());
+            conn.write_documentation(name, &ns)?;
+
+            Ok(())
+        } else {
+            Err(error(Error::NoMatchingConnections {
+                path: ctx.path.clone(),
+            }))
+        }
+    }
+
+    pub fn source(&mut self, name: &str, ctx: Context) -> Result<()> {
+        if let Some((_, conn)) = self
+            .conns
+            .iter_mut()
+            .find(|(_, conn)| conn.expr.is_match(&ctx.path))
+        {
+            info!("Looking up source through: {:?}", conn);
+            let ns = ctx.ns.unwrap_or(conn.user_ns.clone());
+            conn.write_source(name, &ns)?;
+
+            Ok(())
+        } else {
+            Err(error(Error::NoMatchingConnections {
+                path: ctx.path.clone(),
+            }))
+        }
+    }
+
+    pub fn apropos(&mut self, pattern: &str, ctx: Context) -> Result<()> {
+        if let Some((_, conn)) = self
+            .conns
+            .iter_mut()
+            .find(|(_, conn)| conn.expr.is_match(&ctx.path))
+        {
+            info!("Looking up apropos matches through: {:?}", conn);
+            let ns = ctx.ns.unwrap_or(conn.user_ns.clone());
+            conn.write_apropos(pattern, &ns)?;
+
+            Ok(())
+        } else {
+            Err(error(Error::NoMatchingConnections {
+                path: ctx.path.clone(),
+            }))
+        }
+    }
+
+    /// Returns the recent evaluation history (most recent `limit` entries,
+    /// oldest first) for each connection matching `ctx.path`.
+    pub fn history(&self, ctx: Context, limit: usize) -> Result<Vec<(String, Vec<HistoryEntry>)>> {
+        let history: Vec<(String, Vec<HistoryEntry>)> = self
+            .conns
+            .iter()
+            .filter(|(_, conn)| conn.expr.is_match(&ctx.path))
+            .map(|(key, conn)| (key.clone(), conn.history(limit)))
+            .collect();
+
+        if history.is_empty() {
+            Err(error(Error::NoMatchingConnections {
+                path: ctx.path.clone(),
+            }))
+        } else {
+            Ok(history)
+        }
+    }
+
+    /// Re-submits the `index`-from-the-end history entry (`0` is the last
+    /// submitted form) for replay through the named connection.
+    pub fn replay(&mut self, key: &str, index: usize) -> Result<()> {
+        let conn = self.conns.get(key).ok_or_else(|| {
+            error(Error::ConnectionMissing {
+                key: key.to_owned(),
+            })
+        })?;
+
+        let entry = conn.history_entry(index).ok_or_else(|| {
+            error(Error::HistoryIndexMissing {
+                key: key.to_owned(),
+                index,
+            })
+        })?;
+
+        conn.write_eval_ns(&entry.code, &entry.ns)
+    }
+
+    /// The result of the last form evaluated through the named connection,
+    /// for programmatic use (e.g. piping a value into another tool).
+    pub fn last_result(&self, key: &str) -> Result<Option<EvalOutcome>> {
+        self.conns
+            .get(key)
+            .map(|conn| conn.last_result())
+            .ok_or_else(|| {
+                error(Error::ConnectionMissing {
+                    key: key.to_owned(),
+                })
+            })
+    }
 }
@@ -0,0 +1,181 @@
+//! Test-support helpers for exercising `Connection`'s response loops and
+//! `Pool`'s broadcast/pruning logic against a socket, without a live REPL.
+//! Not part of the public API — only ever compiled under `cfg(test)`.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// One scripted step a `MockServer` plays back to a connecting client, in
+/// order. Lets tests exercise both the happy path and the fault conditions
+/// a real REPL socket can produce.
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Write these raw bytes verbatim.
+    Write(Vec<u8>),
+    /// Sleep before continuing, to simulate a slow/laggy REPL.
+    Delay(Duration),
+    /// Close the socket immediately, mid-stream.
+    Drop,
+    /// Stop writing anything further, without closing the socket.
+    Stall,
+}
+
+impl Step {
+    pub fn line(msg: &str) -> Self {
+        Step::Write(format!("{}\n", msg).into_bytes())
+    }
+
+    /// A message cut off halfway through, to exercise malformed-payload
+    /// handling.
+    pub fn truncated(msg: &str) -> Self {
+        let bytes = msg.as_bytes();
+        Step::Write(bytes[..bytes.len() / 2].to_vec())
+    }
+}
+
+/// A throwaway REPL socket server for integration tests: accepts connections
+/// in a loop (a real `Connection` opens seven sockets — eval, heartbeat,
+/// go-to-definition, completions, documentation, source, apropos — plus a
+/// short-lived nREPL `clone` probe, all against the same `addr`) and plays
+/// the same `script` back against each one independently.
+pub struct MockServer {
+    pub addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+}
+
+impl MockServer {
+    pub fn start(script: Vec<Step>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind mock server");
+        let addr = listener.local_addr().expect("mock server has no local addr");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop_thread.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+
+                let script = script.clone();
+                let stop_connection = stop_thread.clone();
+
+                thread::spawn(move || {
+                    for step in script {
+                        if stop_connection.load(Ordering::Relaxed) {
+                            return;
+                        }
+
+                        match step {
+                            Step::Write(bytes) => {
+                                if stream.write_all(&bytes).is_err() {
+                                    return;
+                                }
+                            }
+                            Step::Delay(duration) => thread::sleep(duration),
+                            Step::Drop => return,
+                            Step::Stall => {
+                                // Hold the socket open without writing anything
+                                // further, until the peer gives up and closes it.
+                                let mut buf = [0u8; 1024];
+                                while let Ok(n) = stream.read(&mut buf) {
+                                    if n == 0 {
+                                        break;
+                                    }
+                                }
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        Self { addr, stop }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    #[test]
+    fn plays_back_scripted_writes_in_order() {
+        let server = MockServer::start(vec![Step::line("ret> 1"), Step::line("ret> 2")]);
+
+        let mut stream = TcpStream::connect(server.addr).expect("failed to connect");
+        let mut received = String::new();
+        stream.read_to_string(&mut received).expect("failed to read");
+
+        assert_eq!(received, "ret> 1\nret> 2\n");
+    }
+
+    #[test]
+    fn drop_closes_the_socket_without_finishing_the_script() {
+        let server = MockServer::start(vec![Step::line("ret> 1"), Step::Drop, Step::line("ret> 2")]);
+
+        let mut stream = TcpStream::connect(server.addr).expect("failed to connect");
+        let mut received = String::new();
+        stream.read_to_string(&mut received).expect("failed to read");
+
+        assert_eq!(received, "ret> 1\n");
+    }
+
+    #[test]
+    fn truncated_cuts_the_message_in_half() {
+        match Step::truncated("0123456789") {
+            Step::Write(bytes) => assert_eq!(bytes, b"01234"),
+            _ => panic!("expected a Write step"),
+        }
+    }
+
+    #[test]
+    fn serves_each_accepted_connection_independently() {
+        // A real `Connection` opens several sockets against the same addr
+        // (eval, go-to-definition, completions, ...); the mock must be able
+        // to serve all of them, not just the first.
+        let server = MockServer::start(vec![Step::line("ret> 1")]);
+
+        let mut first = TcpStream::connect(server.addr).expect("failed to connect");
+        let mut second = TcpStream::connect(server.addr).expect("failed to connect");
+
+        let mut received_first = String::new();
+        first
+            .read_to_string(&mut received_first)
+            .expect("failed to read");
+
+        let mut received_second = String::new();
+        second
+            .read_to_string(&mut received_second)
+            .expect("failed to read");
+
+        assert_eq!(received_first, "ret> 1\n");
+        assert_eq!(received_second, "ret> 1\n");
+    }
+}
+
+// Scope note: the request asks for tests proving `Connection` classifies
+// `Ret`/`Out`/`Err`/garbage correctly and that `Pool::eval` prunes broken
+// connections, on top of this harness. Neither is possible from this file —
+// `Connection::connect` opens its sockets through `repl::Client`, and
+// `repl.rs` isn't part of this source tree, so there's no way to construct a
+// `Connection` here, let alone drive it through `MockServer`. What's implemented is the harness itself (this
+// file), which is everything `Connection`/`Pool` tests would need once
+// `repl::Client` exists to drive through it; the classification/pruning
+// tests themselves belong in pool.rs alongside `Connection` and `Pool`.